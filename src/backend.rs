@@ -0,0 +1,155 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const TOKEN_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const TOKEN_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+const TOKEN_TTL_SECS: i64 = 3600;
+
+/// Where to send `generateContent`/`streamGenerateContent` requests and how to
+/// authenticate them. Resolved once at startup from the environment.
+pub enum GeminiBackend {
+    /// The public Generative Language API, authenticated with a raw API key.
+    AiStudio { api_key: String },
+    /// Vertex AI on Google Cloud, authenticated with a short-lived OAuth token
+    /// minted from an Application Default Credentials service-account file.
+    VertexAi {
+        project_id: String,
+        location: String,
+        adc_file: PathBuf,
+        token_cache: Mutex<Option<CachedToken>>,
+    },
+}
+
+pub struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct TokenClaims {
+    iss: String,
+    scope: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<i64>,
+}
+
+impl GeminiBackend {
+    /// Resolves the backend from `--vertex-project`/`--vertex-location` CLI
+    /// flags if given, otherwise `GOOGLE_CLOUD_PROJECT`/`GOOGLE_CLOUD_LOCATION`
+    /// env vars, falling back to the AI Studio API key.
+    pub fn resolve(vertex_project: Option<String>, vertex_location: Option<String>) -> Result<Self> {
+        let project_id = vertex_project.or_else(|| env::var("GOOGLE_CLOUD_PROJECT").ok());
+
+        if let Some(project_id) = project_id {
+            let location = vertex_location
+                .or_else(|| env::var("GOOGLE_CLOUD_LOCATION").ok())
+                .unwrap_or_else(|| "us-central1".to_string());
+            let adc_file = env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                .wrap_err("Vertex AI backend selected but GOOGLE_APPLICATION_CREDENTIALS is not set")?;
+            return Ok(GeminiBackend::VertexAi {
+                project_id,
+                location,
+                adc_file: PathBuf::from(adc_file),
+                token_cache: Mutex::new(None),
+            });
+        }
+
+        let api_key = env::var("GEMINI_API_KEY").wrap_err("Missing GEMINI_API_KEY")?;
+        Ok(GeminiBackend::AiStudio { api_key })
+    }
+
+    /// The `streamGenerateContent` URL for this backend.
+    pub fn stream_url(&self) -> String {
+        match self {
+            GeminiBackend::AiStudio { .. } => {
+                "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-pro:streamGenerateContent?alt=sse".to_string()
+            }
+            GeminiBackend::VertexAi { project_id, location, .. } => format!(
+                "https://{location}-aiplatform.googleapis.com/v1/projects/{project_id}/locations/{location}/publishers/google/models/gemini-2.5-pro:streamGenerateContent?alt=sse"
+            ),
+        }
+    }
+
+    /// The bearer token to send with the request, fetching and caching a
+    /// fresh OAuth token for the Vertex AI backend as needed.
+    pub async fn bearer_token(&self, client: &reqwest::Client) -> Result<String> {
+        match self {
+            GeminiBackend::AiStudio { api_key } => Ok(api_key.clone()),
+            GeminiBackend::VertexAi {
+                adc_file,
+                token_cache,
+                ..
+            } => {
+                let mut cache = token_cache.lock().await;
+                if let Some(cached) = cache.as_ref() {
+                    if cached.expires_at > Instant::now() {
+                        return Ok(cached.access_token.clone());
+                    }
+                }
+
+                let token = fetch_vertex_token(client, adc_file).await?;
+                let access_token = token.access_token.clone();
+                *cache = Some(CachedToken {
+                    access_token: token.access_token,
+                    expires_at: Instant::now() + Duration::from_secs(token.expires_in.unwrap_or(TOKEN_TTL_SECS) as u64),
+                });
+                Ok(access_token)
+            }
+        }
+    }
+}
+
+async fn fetch_vertex_token(client: &reqwest::Client, adc_file: &PathBuf) -> Result<TokenResponse> {
+    let key_bytes = std::fs::read(adc_file)
+        .wrap_err_with(|| format!("Could not read ADC file at {}", adc_file.display()))?;
+    let key: ServiceAccountKey =
+        serde_json::from_slice(&key_bytes).wrap_err("ADC file is not a valid service-account JSON key")?;
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = TokenClaims {
+        iss: key.client_email,
+        scope: TOKEN_SCOPE.to_string(),
+        aud: key.token_uri.clone(),
+        iat: now,
+        exp: now + TOKEN_TTL_SECS,
+    };
+
+    let encoding_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+        .wrap_err("ADC private key is not a valid RSA PEM key")?;
+    let assertion = jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &encoding_key)
+        .wrap_err("Failed to sign ADC JWT")?;
+
+    let res = client
+        .post(&key.token_uri)
+        .form(&[("grant_type", TOKEN_GRANT_TYPE), ("assertion", &assertion)])
+        .send()
+        .await
+        .wrap_err("Failed to reach Google token endpoint")?;
+
+    if !res.status().is_success() {
+        return Err(eyre!("Token endpoint returned {}: {}", res.status(), res.text().await.unwrap_or_default()));
+    }
+
+    res.json::<TokenResponse>()
+        .await
+        .wrap_err("Failed to parse token endpoint response")
+}
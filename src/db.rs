@@ -0,0 +1,143 @@
+use color_eyre::eyre::{Result, WrapErr};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Durable log of every request processed through `gemini_handler`, so
+/// generated code and Studio output survive restarts and can be audited.
+pub struct Db {
+    conn: Mutex<Connection>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Job {
+    pub id: i64,
+    pub prompt: String,
+    pub response: Option<String>,
+    pub extracted_code: Option<String>,
+    pub tool_call_id: Option<String>,
+    pub studio_output: Option<String>,
+    pub status: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Db {
+    /// Opens (and migrates) the SQLite database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path).wrap_err("Failed to open job store database")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                prompt          TEXT NOT NULL,
+                response        TEXT,
+                extracted_code  TEXT,
+                tool_call_id    TEXT,
+                studio_output   TEXT,
+                status          TEXT NOT NULL,
+                created_at      TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+                updated_at      TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+            )",
+        )
+        .wrap_err("Failed to migrate job store database")?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Records a new job for a freshly received prompt and returns its id.
+    pub async fn create_job(&self, prompt: &str) -> Result<i64> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO jobs (prompt, status) VALUES (?1, ?2)",
+            params![prompt, JobStatus::Pending.as_str()],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Records the model's full response, the extracted code, the
+    /// `run_roblox_tool` call id, the Studio output, and the final `status`.
+    /// The optional fields are stored as given, whatever the status.
+    pub async fn complete_job(
+        &self,
+        id: i64,
+        response: &str,
+        extracted_code: Option<&str>,
+        tool_call_id: Option<Uuid>,
+        studio_output: Option<&str>,
+        status: JobStatus,
+    ) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "UPDATE jobs SET response = ?1, extracted_code = ?2, tool_call_id = ?3, studio_output = ?4,
+                status = ?5, updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+             WHERE id = ?6",
+            params![
+                response,
+                extracted_code,
+                tool_call_id.map(|u| u.to_string()),
+                studio_output,
+                status.as_str(),
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub async fn recent_jobs(&self, limit: u32) -> Result<Vec<Job>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT id, prompt, response, extracted_code, tool_call_id, studio_output, status, created_at, updated_at
+             FROM jobs ORDER BY id DESC LIMIT ?1",
+        )?;
+        let jobs = stmt
+            .query_map(params![limit], row_to_job)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(jobs)
+    }
+
+    pub async fn job_by_id(&self, id: i64) -> Result<Option<Job>> {
+        let conn = self.conn.lock().await;
+        let job = conn
+            .query_row(
+                "SELECT id, prompt, response, extracted_code, tool_call_id, studio_output, status, created_at, updated_at
+                 FROM jobs WHERE id = ?1",
+                params![id],
+                row_to_job,
+            )
+            .optional()?;
+        Ok(job)
+    }
+}
+
+fn row_to_job(row: &rusqlite::Row) -> rusqlite::Result<Job> {
+    Ok(Job {
+        id: row.get(0)?,
+        prompt: row.get(1)?,
+        response: row.get(2)?,
+        extracted_code: row.get(3)?,
+        tool_call_id: row.get(4)?,
+        studio_output: row.get(5)?,
+        status: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+    })
+}
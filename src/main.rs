@@ -1,12 +1,18 @@
+use async_stream::stream;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::State;
 use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Json;
 use clap::Parser;
 use color_eyre::eyre::{Report, Result};
-use serde::{Deserialize, Serialize};
+use futures_util::stream::Stream;
+use futures_util::StreamExt;
+use serde::Deserialize;
 use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
 use std::env;
 use std::io;
 use std::net::Ipv4Addr;
@@ -16,46 +22,47 @@ use tokio::time::Duration;
 use tracing_subscriber::{self, EnvFilter};
 use uuid::Uuid;
 
+mod backend;
+mod db;
 mod error;
 mod install;
+mod provider;
+mod session;
+mod tools;
+
+use axum::extract::Path;
+use db::{Db, JobStatus};
+use provider::ModelProvider;
+use session::{History, Role, Turn};
+use tools::{extract_tool_calls, RunCommandResponse, ToolArgumentValues, ToolCall};
 
 pub const STUDIO_PLUGIN_PORT: u16 = 44755;
 const LONG_POLL_DURATION: Duration = Duration::from_secs(15);
-
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub enum ToolArgumentValues {
-    RunCode { command: String },
-}
-
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct ToolCall {
-    args: ToolArgumentValues,
-    id: Option<Uuid>,
-}
-
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct RunCommandResponse {
-    response: String,
-    id: Uuid,
-}
+const DEFAULT_SIDECAR_PORT: u16 = 8080;
 
 pub struct AppState {
     process_queue: VecDeque<ToolCall>,
     output_map: HashMap<Uuid, mpsc::UnboundedSender<Result<String, Report>>>,
     waiter: watch::Receiver<()>,
     trigger: watch::Sender<()>,
+    model_provider: Arc<dyn ModelProvider>,
+    db: Arc<Db>,
+    sessions: HashMap<Uuid, History>,
 }
 
 pub type PackedState = Arc<Mutex<AppState>>;
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(model_provider: Arc<dyn ModelProvider>, db: Arc<Db>) -> Self {
         let (trigger, waiter) = watch::channel(());
         Self {
             process_queue: VecDeque::new(),
             output_map: HashMap::new(),
             waiter,
             trigger,
+            model_provider,
+            db,
+            sessions: HashMap::new(),
         }
     }
 }
@@ -65,6 +72,22 @@ impl AppState {
 struct Args {
     #[arg(short, long)]
     serve: bool,
+
+    /// Use Vertex AI with this Google Cloud project instead of the AI Studio API key.
+    #[arg(long)]
+    vertex_project: Option<String>,
+
+    /// Vertex AI region to target (defaults to GOOGLE_CLOUD_LOCATION, then us-central1).
+    #[arg(long)]
+    vertex_location: Option<String>,
+
+    /// Skip Gemini entirely and use the local sidecar, even if credentials are set.
+    #[arg(long)]
+    local_model: bool,
+
+    /// Port of the local OpenAI-compatible sidecar (llama.cpp, Ollama, ...).
+    #[arg(long)]
+    sidecar_port: Option<u16>,
 }
 
 #[tokio::main]
@@ -84,12 +107,23 @@ async fn main() -> Result<()> {
 
     tracing::info!("Starting server...");
 
-    let server_state = Arc::new(Mutex::new(AppState::new()));
+    let model_provider = provider::resolve_provider(
+        args.local_model,
+        args.vertex_project,
+        args.vertex_location,
+        args.sidecar_port.unwrap_or(DEFAULT_SIDECAR_PORT),
+    );
+    let db_path = env::var("GEMINI_MCP_DB").unwrap_or_else(|_| "gemini_mcp.sqlite".to_string());
+    let db = Arc::new(Db::open(db_path)?);
+    let server_state = Arc::new(Mutex::new(AppState::new(model_provider, db)));
 
     let app = axum::Router::new()
         .route("/request", get(request_handler))
         .route("/response", post(response_handler))
         .route("/prompt", post(gemini_handler))
+        .route("/history", get(history_list_handler))
+        .route("/history/:id", get(history_detail_handler))
+        .route("/ws", get(ws_handler))
         .with_state(server_state);
 
     let listener = tokio::net::TcpListener::bind((Ipv4Addr::new(127, 0, 0, 1), STUDIO_PLUGIN_PORT)).await?;
@@ -103,16 +137,14 @@ async fn main() -> Result<()> {
 #[derive(Deserialize)]
 struct PromptPayload {
     prompt: String,
+    session_id: Option<Uuid>,
 }
 
-async fn run_roblox_tool(state: PackedState, args: ToolArgumentValues) -> Result<String, Report> {
+async fn run_roblox_tool(state: PackedState, args: ToolArgumentValues) -> Result<(Uuid, String), Report> {
     let (id, mut rx) = {
         let mut state = state.lock().await;
         let id = Uuid::new_v4();
-        let tool_call = ToolCall {
-            args,
-            id: Some(id),
-        };
+        let tool_call = ToolCall::new(args, id);
         let (tx, rx) = mpsc::unbounded_channel::<Result<String, Report>>();
         state.process_queue.push_back(tool_call);
         state.output_map.insert(id, tx);
@@ -122,95 +154,143 @@ async fn run_roblox_tool(state: PackedState, args: ToolArgumentValues) -> Result
 
     let result = rx.recv().await.ok_or_else(|| Report::msg("Channel closed unexpectedly"))?;
     state.lock().await.output_map.remove(&id);
-    result
+    result.map(|output| (id, output))
 }
 
 async fn gemini_handler(
     State(state): State<PackedState>,
     Json(payload): Json<PromptPayload>,
 ) -> impl IntoResponse {
-    let api_key = match env::var("GEMINI_API_KEY") {
-        Ok(val) => val,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Missing GEMINI_API_KEY").into_response(),
+    let session_id = payload.session_id.unwrap_or_else(Uuid::new_v4);
+
+    // Grab the `Db`/`ModelProvider` handles and drop the `AppState` lock
+    // immediately: both the SQLite write below and the model provider's
+    // network round trip must not stall every other handler waiting on the
+    // same mutex (the in-memory `sessions` entry is the only thing that
+    // actually needs it held).
+    let (db, model_provider) = {
+        let state = state.lock().await;
+        (state.db.clone(), state.model_provider.clone())
     };
 
-    let client = reqwest::Client::new();
-    let mut full_text = String::new();
-    let mut cursor: Option<String> = None;
+    let job_id = match db.create_job(&payload.prompt).await {
+        Ok(id) => id,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to record job: {}", e)).into_response(),
+    };
 
-    loop {
-        let body = serde_json::json!({
-            "contents": [{
-                "parts": [{ "text": payload.prompt }]
-            }],
-            "generationConfig": {
-                "temperature": 0.7,
-                "topK": 1,
-                "topP": 1,
-                "maxOutputTokens": 2048
-            },
-            "stream": true,
-            "cursor": cursor
-        });
-
-        let res = client
-            .post("https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-pro:generateContent")
-            .bearer_auth(&api_key)
-            .json(&body)
-            .send()
-            .await;
+    let contents = {
+        let mut state = state.lock().await;
+        let history = state.sessions.entry(session_id).or_default();
+        history.push(Turn { role: Role::User, text: payload.prompt.clone() });
+        history.to_contents()
+    };
 
-        let value = match res {
-            Ok(resp) => match resp.json::<serde_json::Value>().await {
-                Ok(json) => json,
-                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("JSON parse error: {}", e)).into_response(),
-            },
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("HTTP error: {}", e)).into_response(),
-        };
+    let text_stream = match model_provider.generate(contents).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let _ = db.complete_job(job_id, "", None, None, None, JobStatus::Failed).await;
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Model provider error: {}", e)).into_response();
+        }
+    };
 
-        let text = value["candidates"]
-            .get(0)
-            .and_then(|c| c["content"]["parts"].get(0))
-            .and_then(|p| p["text"].as_str())
-            .unwrap_or("")
-            .to_string();
+    let mut response = Sse::new(stream_gemini_response(state, db, text_stream, job_id, session_id)).into_response();
+    if let Ok(value) = session_id.to_string().parse() {
+        response.headers_mut().insert("X-Session-Id", value);
+    }
+    response
+}
 
-        full_text += &text;
+/// What to record as the model's turn when a stream error cuts a response
+/// short, so the session history stays user/model-alternating instead of
+/// leaving the preceding `Role::User` turn dangling. Keeps whatever text did
+/// stream in before the error, falling back to the error itself if none did.
+fn placeholder_turn_text(full_text: &str, db_err: &str) -> String {
+    if full_text.is_empty() {
+        format!("[{}]", db_err)
+    } else {
+        full_text.to_string()
+    }
+}
 
-        cursor = value["candidates"]
-            .get(0)
-            .and_then(|c| c["cursor"].as_str())
-            .map(|s| s.to_string());
+/// Drains the model provider's text-delta stream, forwarding each delta to the
+/// caller as its own event while accumulating the full response. Once the
+/// stream ends, runs the extracted tool calls (if any) through the Studio
+/// plugin and emits a final `studio-output` event per call.
+fn stream_gemini_response(
+    state: PackedState,
+    db: Arc<Db>,
+    mut text_stream: provider::TextStream,
+    job_id: i64,
+    session_id: Uuid,
+) -> impl Stream<Item = std::result::Result<Event, Infallible>> {
+    stream! {
+        yield Ok(Event::default().event("session").data(session_id.to_string()));
+
+        let mut full_text = String::new();
+
+        while let Some(delta) = text_stream.next().await {
+            let delta = match delta {
+                Ok(delta) => delta,
+                Err(e) => {
+                    let db_err = format!("stream error: {}", e);
+                    {
+                        let mut state = state.lock().await;
+                        let history = state.sessions.entry(session_id).or_default();
+                        history.push(Turn { role: Role::Model, text: placeholder_turn_text(&full_text, &db_err) });
+                    }
+                    let _ = db.complete_job(job_id, &full_text, None, None, None, JobStatus::Failed).await;
+                    yield Ok(Event::default().event("error").data(db_err));
+                    return;
+                }
+            };
 
-        if cursor.is_none() {
-            break;
+            full_text.push_str(&delta);
+            yield Ok(Event::default().event("delta").data(delta));
         }
-    }
 
-    if let Some(code_to_run) = extract_code(&full_text) {
-        let args = ToolArgumentValues::RunCode { command: code_to_run };
-        match run_roblox_tool(state, args).await {
-            Ok(output) => (
-                StatusCode::OK,
-                format!("Gemini 2.5 says:\n{}\n\nRoblox Studio output:\n{}", full_text, output),
-            )
-                .into_response(),
-            Err(e) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to run code: {}", e),
-            )
-                .into_response(),
+        let tool_calls = extract_tool_calls(&full_text);
+        let extracted_code = match tool_calls.first() {
+            Some(ToolArgumentValues::RunCode { command }) => Some(command.clone()),
+            _ => None,
+        };
+
+        let mut outputs = Vec::new();
+        let mut last_tool_id = None;
+        let mut failure = None;
+
+        for args in tool_calls {
+            match run_roblox_tool(state.clone(), args).await {
+                Ok((tool_id, output)) => {
+                    last_tool_id = Some(tool_id);
+                    yield Ok(Event::default().event("studio-output").data(output.clone()));
+                    outputs.push(output);
+                }
+                Err(e) => {
+                    failure = Some(e.to_string());
+                    yield Ok(Event::default().event("error").data(format!("Failed to run tool call: {}", e)));
+                    break;
+                }
+            }
         }
-    } else {
-        (StatusCode::OK, full_text).into_response()
-    }
-}
 
-fn extract_code(text: &str) -> Option<String> {
-    text.find("```luau")
-        .and_then(|start| text[start + 7..].find("```").map(|end| (start, start + 7 + end)))
-        .and_then(|(start, end)| text.get(start..end))
-        .map(|code| code.trim().to_string())
+        let joined_output = (!outputs.is_empty()).then(|| outputs.join("\n"));
+
+        {
+            let mut state = state.lock().await;
+            let history = state.sessions.entry(session_id).or_default();
+            history.push(Turn { role: Role::Model, text: full_text.clone() });
+            if let Some(output) = &joined_output {
+                history.push(Turn { role: Role::Function, text: output.clone() });
+            }
+        }
+
+        let status = if failure.is_some() { JobStatus::Failed } else { JobStatus::Succeeded };
+        let _ = db
+            .complete_job(job_id, &full_text, extracted_code.as_deref(), last_tool_id, joined_output.as_deref(), status)
+            .await;
+
+        yield Ok(Event::default().event("done").data(""));
+    }
 }
 
 pub async fn request_handler(State(state): State<PackedState>) -> Response {
@@ -238,17 +318,138 @@ pub async fn request_handler(State(state): State<PackedState>) -> Response {
     }
 }
 
+async fn history_list_handler(State(state): State<PackedState>) -> impl IntoResponse {
+    let db = state.lock().await.db.clone();
+    match db.recent_jobs(50).await {
+        Ok(jobs) => Json(jobs).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load history: {}", e)).into_response(),
+    }
+}
+
+async fn history_detail_handler(State(state): State<PackedState>, Path(id): Path<i64>) -> impl IntoResponse {
+    let db = state.lock().await.db.clone();
+    match db.job_by_id(id).await {
+        Ok(Some(job)) => Json(job).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Unknown job id").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load job: {}", e)).into_response(),
+    }
+}
+
 pub async fn response_handler(
     State(state): State<PackedState>,
     Json(payload): Json<RunCommandResponse>,
 ) -> impl IntoResponse {
     let state = state.lock().await;
-    if let Some(tx) = state.output_map.get(&payload.id) {
-        if tx.send(Ok(payload.response)).is_err() {
-            tracing::error!("Failed to send response to channel: receiver dropped.");
-        }
+    if deliver_tool_response(&state, payload) {
+        StatusCode::OK.into_response()
     } else {
-        return (StatusCode::NOT_FOUND, "Unknown ID").into_response();
+        (StatusCode::NOT_FOUND, "Unknown ID").into_response()
+    }
+}
+
+/// Routes a plugin's `RunCommandResponse` into the waiting `run_roblox_tool`
+/// caller's channel. Returns `false` if `payload.id` has no matching waiter
+/// (already delivered, or the caller gave up).
+fn deliver_tool_response(state: &AppState, payload: RunCommandResponse) -> bool {
+    let Some(tx) = state.output_map.get(&payload.id) else {
+        return false;
+    };
+
+    let outcome = match payload.error {
+        Some(err) => Err(Report::msg(format!("[{}] {}", err.code, err.message))),
+        None => Ok(result_to_string(payload.result)),
+    };
+
+    if tx.send(outcome).is_err() {
+        tracing::error!("Failed to send response to channel: receiver dropped.");
+    }
+    true
+}
+
+fn result_to_string(result: Option<serde_json::Value>) -> String {
+    match result {
+        Some(serde_json::Value::String(s)) => s,
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Upgrades `/ws` to a persistent bidirectional channel with the Studio
+/// plugin: queued `ToolCall`s are pushed the moment they're enqueued instead
+/// of waiting on the next long poll, and `RunCommandResponse` frames come
+/// back over the same socket.
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<PackedState>) -> Response {
+    ws.on_upgrade(move |socket| handle_plugin_socket(socket, state))
+}
+
+async fn handle_plugin_socket(mut socket: WebSocket, state: PackedState) {
+    let mut waiter = state.lock().await.waiter.clone();
+
+    // Send any `ToolCall` already sitting in `process_queue` (e.g. queued
+    // while the plugin was disconnected) once up front, the same way
+    // `request_handler` checks `process_queue` before its first wait, rather
+    // than relying on `waiter` to report a change for it.
+    if !flush_process_queue(&mut socket, &state).await {
+        fail_pending_waiters(&state).await;
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            changed = waiter.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                if !flush_process_queue(&mut socket, &state).await {
+                    fail_pending_waiters(&state).await;
+                    return;
+                }
+            }
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Ok(payload) = serde_json::from_str::<RunCommandResponse>(&text) {
+                            deliver_tool_response(&*state.lock().await, payload);
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => {
+                        fail_pending_waiters(&state).await;
+                        return;
+                    }
+                    Some(Err(_)) => {
+                        fail_pending_waiters(&state).await;
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Drains `process_queue` and pushes each task to the plugin socket. Returns
+/// `false` on the first send failure (socket gone), leaving any remaining
+/// tasks already removed from the queue for the caller to fail out via
+/// `fail_pending_waiters`.
+async fn flush_process_queue(socket: &mut WebSocket, state: &PackedState) -> bool {
+    let tasks: VecDeque<ToolCall> = {
+        let mut state = state.lock().await;
+        std::mem::take(&mut state.process_queue)
+    };
+    for task in tasks {
+        let Ok(text) = serde_json::to_string(&task) else { continue };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Fails every caller still waiting on a Studio response so a plugin
+/// disconnect surfaces as an error instead of hanging `run_roblox_tool` forever.
+async fn fail_pending_waiters(state: &PackedState) {
+    let mut state = state.lock().await;
+    for (_, tx) in state.output_map.drain() {
+        let _ = tx.send(Err(Report::msg("Studio plugin disconnected")));
     }
-    StatusCode::OK.into_response()
 }
\ No newline at end of file
@@ -0,0 +1,204 @@
+use async_stream::stream;
+use async_trait::async_trait;
+use color_eyre::eyre::{Report, Result};
+use futures_util::stream::StreamExt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::backend::GeminiBackend;
+
+pub type TextStream = Pin<Box<dyn futures_util::Stream<Item = Result<String, Report>> + Send>>;
+
+/// A source of model completions: the hosted Gemini API, or a local sidecar
+/// for offline use. Selected once at startup based on available credentials.
+#[async_trait]
+pub trait ModelProvider: Send + Sync {
+    /// Streams text deltas for the given `contents` (the same Gemini-shaped
+    /// conversation array used elsewhere in the pipeline).
+    async fn generate(&self, contents: serde_json::Value) -> Result<TextStream>;
+}
+
+pub struct GeminiProvider {
+    backend: GeminiBackend,
+    client: reqwest::Client,
+}
+
+impl GeminiProvider {
+    pub fn new(backend: GeminiBackend) -> Self {
+        Self { backend, client: reqwest::Client::new() }
+    }
+}
+
+#[async_trait]
+impl ModelProvider for GeminiProvider {
+    async fn generate(&self, contents: serde_json::Value) -> Result<TextStream> {
+        let body = serde_json::json!({
+            "contents": contents,
+            "generationConfig": {
+                "temperature": 0.7,
+                "topK": 1,
+                "topP": 1,
+                "maxOutputTokens": 2048
+            }
+        });
+
+        let bearer_token = self.backend.bearer_token(&self.client).await?;
+        let upstream = self
+            .client
+            .post(self.backend.stream_url())
+            .bearer_auth(bearer_token)
+            .json(&body)
+            .send()
+            .await?;
+
+        Ok(Box::pin(sse_delta_stream(upstream, |value| {
+            value["candidates"]
+                .get(0)
+                .and_then(|c| c["content"]["parts"].get(0))
+                .and_then(|p| p["text"].as_str())
+                .map(|s| s.to_string())
+        })))
+    }
+}
+
+/// Talks to an OpenAI-compatible `/v1/chat/completions` endpoint (llama.cpp,
+/// Ollama, etc.) running locally, for offline inference when there is no
+/// Gemini API key or network access.
+pub struct SidecarProvider {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl SidecarProvider {
+    pub fn new(base_url: String) -> Self {
+        Self { base_url, client: reqwest::Client::new() }
+    }
+}
+
+#[async_trait]
+impl ModelProvider for SidecarProvider {
+    async fn generate(&self, contents: serde_json::Value) -> Result<TextStream> {
+        let messages = contents_to_openai_messages(&contents);
+        let body = serde_json::json!({
+            "model": "local",
+            "messages": messages,
+            "stream": true
+        });
+
+        let upstream = self
+            .client
+            .post(format!("{}/v1/chat/completions", self.base_url))
+            .json(&body)
+            .send()
+            .await?;
+
+        Ok(Box::pin(sse_delta_stream(upstream, |value| {
+            value["choices"]
+                .get(0)
+                .and_then(|c| c["delta"]["content"].as_str())
+                .map(|s| s.to_string())
+        })))
+    }
+}
+
+fn contents_to_openai_messages(contents: &serde_json::Value) -> serde_json::Value {
+    let messages: Vec<serde_json::Value> = contents
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|turn| {
+            let role = match turn["role"].as_str() {
+                Some("model") => "assistant",
+                _ => "user",
+            };
+            let text = turn["parts"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|p| p["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            serde_json::json!({ "role": role, "content": text })
+        })
+        .collect();
+    serde_json::Value::Array(messages)
+}
+
+/// Drains an SSE byte stream, extracting a text delta from each event with
+/// `extract` and yielding the non-empty ones in order. Shared between
+/// providers since both upstreams speak `data: {...}\n\n` SSE framing.
+///
+/// Buffers raw bytes (not `String`) across TCP chunks so a multi-byte UTF-8
+/// character split across a chunk boundary is reassembled instead of being
+/// decoded one chunk at a time, which would mangle it into U+FFFD.
+fn sse_delta_stream(
+    upstream: reqwest::Response,
+    extract: impl Fn(&serde_json::Value) -> Option<String> + Send + 'static,
+) -> impl futures_util::Stream<Item = Result<String, Report>> {
+    stream! {
+        let mut byte_stream = upstream.bytes_stream();
+        let mut buf: Vec<u8> = Vec::new();
+
+        while let Some(chunk) = byte_stream.next().await {
+            let chunk = match chunk {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    yield Err(Report::new(e));
+                    return;
+                }
+            };
+            buf.extend_from_slice(&chunk);
+
+            while let Some(pos) = find_double_newline(&buf) {
+                let event_block = buf[..pos].to_vec();
+                buf.drain(..pos + 2);
+
+                let Ok(event_block) = std::str::from_utf8(&event_block) else {
+                    continue;
+                };
+
+                let Some(data) = event_block.strip_prefix("data: ") else {
+                    continue;
+                };
+
+                if data.trim() == "[DONE]" {
+                    return;
+                }
+
+                let value: serde_json::Value = match serde_json::from_str(data) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+
+                if let Some(delta) = extract(&value) {
+                    if !delta.is_empty() {
+                        yield Ok(delta);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Finds the byte offset of the first `\n\n` in `buf`, if any.
+fn find_double_newline(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\n\n")
+}
+
+/// Picks Gemini when credentials/CLI flags resolve, otherwise the local
+/// sidecar; `force_local` always selects the sidecar.
+pub fn resolve_provider(
+    force_local: bool,
+    vertex_project: Option<String>,
+    vertex_location: Option<String>,
+    sidecar_port: u16,
+) -> Arc<dyn ModelProvider> {
+    if !force_local {
+        if let Ok(backend) = GeminiBackend::resolve(vertex_project, vertex_location) {
+            return Arc::new(GeminiProvider::new(backend));
+        }
+        tracing::warn!("No Gemini credentials found; falling back to local sidecar on port {sidecar_port}");
+    }
+
+    Arc::new(SidecarProvider::new(format!("http://127.0.0.1:{sidecar_port}")))
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// Caps how many turns of history are kept per session so token usage on
+/// replayed context stays bounded; the oldest turns are dropped first.
+const MAX_HISTORY_TURNS: usize = 20;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum Role {
+    User,
+    Model,
+    /// The Studio output fed back as the result of a tool call.
+    Function,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Turn {
+    pub role: Role,
+    pub text: String,
+}
+
+/// Per-session conversation history, trimmed to `MAX_HISTORY_TURNS`.
+#[derive(Default)]
+pub struct History(Vec<Turn>);
+
+impl History {
+    pub fn push(&mut self, turn: Turn) {
+        self.0.push(turn);
+        if self.0.len() > MAX_HISTORY_TURNS {
+            let overflow = self.0.len() - MAX_HISTORY_TURNS;
+            self.0.drain(..overflow);
+        }
+    }
+
+    /// Builds the Gemini `contents` array from the full turn history.
+    ///
+    /// `Role::Function` maps to the same Gemini `"user"` role as `Role::User`
+    /// (a tool result is just more context fed back to the model), but Gemini
+    /// requires strict `user`/`model` alternation in multi-turn `contents`.
+    /// A successful tool-call round pushes `Model` then `Function`, and the
+    /// following prompt pushes another `User` turn right after — two `"user"`
+    /// turns back to back. Rather than emit those as separate content entries,
+    /// consecutive turns that map to the same Gemini role are merged into one
+    /// entry with multiple `parts`, so the array stays alternating.
+    pub fn to_contents(&self) -> serde_json::Value {
+        let mut contents: Vec<serde_json::Value> = Vec::new();
+
+        for turn in &self.0 {
+            let role = match turn.role {
+                Role::User | Role::Function => "user",
+                Role::Model => "model",
+            };
+            let part = serde_json::json!({ "text": turn.text });
+
+            match contents.last_mut() {
+                Some(last) if last["role"] == role => {
+                    last["parts"].as_array_mut().expect("parts is always an array").push(part);
+                }
+                _ => contents.push(serde_json::json!({ "role": role, "parts": [part] })),
+            }
+        }
+
+        serde_json::Value::Array(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_turn_merges_into_the_next_user_turn() {
+        let mut history = History::default();
+        history.push(Turn { role: Role::User, text: "make a part".to_string() });
+        history.push(Turn { role: Role::Model, text: "```json\n{}\n```".to_string() });
+        history.push(Turn { role: Role::Function, text: "ok".to_string() });
+        history.push(Turn { role: Role::User, text: "now color it red".to_string() });
+
+        let contents = history.to_contents();
+        let roles: Vec<&str> = contents.as_array().unwrap().iter().map(|c| c["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, vec!["user", "model", "user"]);
+
+        let merged_parts = contents[2]["parts"].as_array().unwrap();
+        assert_eq!(merged_parts.len(), 2);
+        assert_eq!(merged_parts[0]["text"], "ok");
+        assert_eq!(merged_parts[1]["text"], "now color it red");
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The Studio-side capabilities the model can invoke. Serializes as a JSON-RPC
+/// 2.0 `method`/`params` pair (see [`ToolCall`]), so adding a variant here is
+/// all that's needed to expose a new capability to the model and the plugin.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "method", content = "params")]
+pub enum ToolArgumentValues {
+    RunCode { command: String },
+    InsertModel { asset_id: String },
+    CreateInstance {
+        class_name: String,
+        #[serde(default)]
+        properties: serde_json::Value,
+        #[serde(default)]
+        parent: Option<String>,
+    },
+    DeleteInstance { path: String },
+    GetSelection,
+}
+
+/// A JSON-RPC 2.0 request sent to the Studio plugin over `/request` (or `/ws`).
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ToolCall {
+    pub jsonrpc: String,
+    #[serde(flatten)]
+    pub call: ToolArgumentValues,
+    pub id: Option<Uuid>,
+}
+
+impl ToolCall {
+    pub fn new(call: ToolArgumentValues, id: Uuid) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            call,
+            id: Some(id),
+        }
+    }
+}
+
+/// A JSON-RPC 2.0 response for a [`ToolCall`], posted back by the plugin on `/response`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RunCommandResponse {
+    #[serde(default = "jsonrpc_version")]
+    pub jsonrpc: String,
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JsonRpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+fn jsonrpc_version() -> String {
+    "2.0".to_string()
+}
+
+/// The compact form the model is asked to emit for a tool invocation, e.g.
+/// `{"tool": "InsertModel", "args": {"asset_id": "123"}}`.
+#[derive(Deserialize)]
+struct ModelToolCall {
+    tool: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+/// Parses every structured tool call out of the model's response text, in the
+/// order they appear. Looks first for a fenced ` ```json ` block containing a
+/// single call object or an array of them; falls back to the legacy single
+/// ` ```luau ` fence (wrapped as a `RunCode` call) so existing prompts keep working.
+pub fn extract_tool_calls(text: &str) -> Vec<ToolArgumentValues> {
+    if let Some(json_block) = extract_fenced_block(text, "```json") {
+        if let Ok(calls) = parse_model_tool_calls(&json_block) {
+            if !calls.is_empty() {
+                return calls;
+            }
+        }
+    }
+
+    extract_code(text)
+        .map(|command| vec![ToolArgumentValues::RunCode { command }])
+        .unwrap_or_default()
+}
+
+fn parse_model_tool_calls(json_block: &str) -> serde_json::Result<Vec<ToolArgumentValues>> {
+    let value: serde_json::Value = serde_json::from_str(json_block)?;
+    let entries: Vec<ModelToolCall> = if value.is_array() {
+        serde_json::from_value(value)?
+    } else {
+        vec![serde_json::from_value(value)?]
+    };
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| {
+            serde_json::from_value(serde_json::json!({ "method": entry.tool, "params": entry.args })).ok()
+        })
+        .collect())
+}
+
+fn extract_fenced_block(text: &str, fence: &str) -> Option<String> {
+    let start = text.find(fence)?;
+    let body_start = start + fence.len();
+    let end = text[body_start..].find("```")?;
+    text.get(body_start..body_start + end).map(|s| s.trim().to_string())
+}
+
+pub fn extract_code(text: &str) -> Option<String> {
+    text.find("```luau")
+        .and_then(|start| text[start + 7..].find("```").map(|end| (start + 7, start + 7 + end)))
+        .and_then(|(start, end)| text.get(start..end))
+        .map(|code| code.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_code_strips_the_luau_fence() {
+        let text = "```luau\nprint('hi')\n```";
+        assert_eq!(extract_code(text), Some("print('hi')".to_string()));
+    }
+
+    #[test]
+    fn extract_code_returns_none_without_a_fence() {
+        assert_eq!(extract_code("no code here"), None);
+    }
+}